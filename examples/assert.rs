@@ -0,0 +1,16 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all)]
+
+use naptime::Naptime;
+use tracing::info;
+
+#[tokio::main]
+async fn main() {
+  tracing_subscriber::fmt().init();
+
+  let assertion = Naptime::prevent_idle_system_sleep("naptime assert example").unwrap();
+
+  info!("holding the machine awake. Ctrl-C to let it sleep again");
+  tokio::signal::ctrl_c().await.unwrap();
+  drop(assertion);
+}
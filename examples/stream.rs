@@ -0,0 +1,47 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all)]
+
+// PowerEvent and Naptime::event_stream are macOS-only (see src/lib.rs), so this example only
+// builds there.
+#[cfg(not(target_os = "macos"))]
+fn main() {
+  eprintln!("this example only runs on macOS");
+}
+
+#[cfg(target_os = "macos")]
+use futures::StreamExt;
+#[cfg(target_os = "macos")]
+use naptime::{Naptime, PowerEvent};
+#[cfg(target_os = "macos")]
+use tracing::info;
+
+#[cfg(target_os = "macos")]
+#[tokio::main]
+async fn main() {
+  tracing_subscriber::fmt().init();
+  info!("hello!");
+
+  let (naptime, mut events) = Naptime::event_stream().unwrap();
+
+  info!("waiting forever. good luck. Ctrl-C to kill");
+  loop {
+    tokio::select! {
+      evt = events.next() => {
+        match evt {
+          Some(PowerEvent::SleepQuery(ack)) => {
+            info!("SleepQuery");
+            ack.allow();
+          }
+          Some(PowerEvent::SleepFailed) => info!("SleepFailed"),
+          Some(PowerEvent::Sleep) => info!("Sleep"),
+          Some(PowerEvent::Wake) => info!("Wake"),
+          None => break,
+        }
+      }
+      _ = tokio::signal::ctrl_c() => break,
+    }
+  }
+
+  info!("dropping naptime");
+  drop(naptime);
+}
@@ -0,0 +1,47 @@
+#![forbid(unsafe_code)]
+#![warn(clippy::all)]
+
+// DeviceEventHandler, io_service_t, and Naptime::watch_matching are macOS-only (see src/lib.rs),
+// so this example only builds there.
+#[cfg(not(target_os = "macos"))]
+fn main() {
+  eprintln!("this example only runs on macOS");
+}
+
+#[cfg(target_os = "macos")]
+use naptime::{DeviceEventHandler, EventHandler, Naptime};
+#[cfg(target_os = "macos")]
+use tracing::info;
+
+#[cfg(target_os = "macos")]
+struct NoOp {}
+#[cfg(target_os = "macos")]
+impl EventHandler for NoOp {}
+
+#[cfg(target_os = "macos")]
+struct UsbLogger {}
+#[cfg(target_os = "macos")]
+impl DeviceEventHandler for UsbLogger {
+  fn device_added(&mut self, service: naptime::io_service_t) {
+    info!("device added: {service}");
+  }
+
+  fn device_removed(&mut self, service: naptime::io_service_t) {
+    info!("device removed: {service}");
+  }
+}
+
+#[cfg(target_os = "macos")]
+#[tokio::main]
+async fn main() {
+  tracing_subscriber::fmt().init();
+
+  let mut naptime = Naptime::new(NoOp {}).unwrap();
+  naptime
+    .watch_matching("IOUSBDevice", None, None, UsbLogger {})
+    .unwrap();
+
+  info!("watching for USB hotplug. Ctrl-C to stop");
+  tokio::signal::ctrl_c().await.unwrap();
+  drop(naptime);
+}
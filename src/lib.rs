@@ -1,10 +1,35 @@
 #![warn(clippy::all)]
 
+#[cfg(target_os = "macos")]
+use std::{
+  pin::Pin,
+  task::{Context, Poll},
+};
+
+#[cfg(target_os = "macos")]
+use futures::Stream;
+#[cfg(target_os = "macos")]
+use tokio::sync::mpsc;
+
 #[cfg(target_os = "macos")]
 mod macos;
 #[cfg(target_os = "macos")]
-pub use macos::Naptime;
+pub use macos::{io_service_t, DeviceEventHandler, Naptime, NaptimeConfig, PowerAssertion, SleepAck};
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "linux")]
+pub use linux::{Naptime, PowerAssertion, SleepAck};
 
+#[cfg(target_os = "windows")]
+mod windows;
+#[cfg(target_os = "windows")]
+pub use windows::{Naptime, PowerAssertion, SleepAck};
+
+/// A handler's answer to [`EventHandler::sleep_query`]. Whether `Deny` actually prevents sleep
+/// depends on the platform: macOS can veto outright via `IOCancelPowerChange`, Linux's logind can
+/// only delay it via a delay-mode inhibitor (best-effort, bounded by `InhibitDelayMaxSec`), and
+/// Windows has no veto mechanism at all, so `Deny` there is logged and otherwise ignored.
 pub enum SleepQueryResponse {
   Allow,
   Deny,
@@ -14,11 +39,106 @@ pub trait EventHandler: Send + 'static {
   fn sleep_query(&mut self) -> SleepQueryResponse {
     SleepQueryResponse::Allow
   }
+
+  /// Like [`EventHandler::sleep_query`], but hands over the underlying acknowledgment token instead
+  /// of requiring an immediate answer. The token may be moved elsewhere (e.g. onto a tokio task) and
+  /// acknowledged later, as long as it beats the platform's grace window (30 seconds on macOS, a few
+  /// seconds on Linux, and effectively none on Windows — see [`SleepAck`]). The default
+  /// implementation just forwards to `sleep_query` and acks with its answer immediately.
+  fn sleep_query_deferred(&mut self, ack: SleepAck) {
+    match self.sleep_query() {
+      SleepQueryResponse::Allow => ack.allow(),
+      SleepQueryResponse::Deny => ack.cancel(),
+    }
+  }
+
   fn sleep_failed(&mut self) {}
   fn sleep(&mut self) {}
+
+  /// Like [`EventHandler::sleep`], but hands over the acknowledgment token so the handler can finish
+  /// in-flight work (flushing a database, completing a print job, etc.) before acknowledging, as
+  /// long as it beats macOS's 30 second grace window. The default implementation just calls `sleep`
+  /// and acks immediately. Only the macOS backend can actually defer here: Windows calls this
+  /// synchronously with a token whose `allow` does nothing, and the Linux backend never calls this
+  /// method, calling [`EventHandler::sleep`] directly instead.
+  fn sleep_deferred(&mut self, ack: SleepAck) {
+    self.sleep();
+    ack.allow();
+  }
+
   fn wake(&mut self) {}
 }
 
 #[derive(Debug, thiserror::Error)]
 #[error("{0}")]
 pub struct Error(String);
+
+/// An event produced by the [`Naptime::event_stream`] constructor, mirroring the callbacks on
+/// [`EventHandler`] one-for-one. [`PowerEvent::SleepQuery`] carries the same deferred [`SleepAck`]
+/// token [`EventHandler::sleep_query_deferred`] gets, so an async consumer can `await` whatever it
+/// needs before allowing or denying the sleep, as long as it beats the platform's grace window.
+#[cfg(target_os = "macos")]
+pub enum PowerEvent {
+  SleepQuery(SleepAck),
+  SleepFailed,
+  Sleep,
+  Wake,
+}
+
+/// A `futures::Stream` of [`PowerEvent`]s, returned alongside a [`Naptime`] by
+/// [`Naptime::event_stream`]. Backed by a `tokio::sync::mpsc` unbounded channel fed from the
+/// platform's run-loop thread, so polling it never blocks that thread.
+#[cfg(target_os = "macos")]
+pub struct EventStream(mpsc::UnboundedReceiver<PowerEvent>);
+
+#[cfg(target_os = "macos")]
+impl EventStream {
+  pub(crate) fn new(rx: mpsc::UnboundedReceiver<PowerEvent>) -> Self {
+    Self(rx)
+  }
+}
+
+#[cfg(target_os = "macos")]
+impl Stream for EventStream {
+  type Item = PowerEvent;
+
+  fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+    self.0.poll_recv(cx)
+  }
+}
+
+/// An [`EventHandler`] that forwards every callback into a channel as a [`PowerEvent`], backing
+/// [`Naptime::event_stream`]. The channel's receiving end is the separately returned
+/// [`EventStream`]; once that is dropped, `send` failures here are simply ignored — there's no one
+/// left to notify. Dropping the `Naptime` drops this handler, which ends the stream.
+#[cfg(target_os = "macos")]
+pub(crate) struct StreamHandler {
+  tx: mpsc::UnboundedSender<PowerEvent>,
+}
+
+#[cfg(target_os = "macos")]
+impl StreamHandler {
+  pub(crate) fn new(tx: mpsc::UnboundedSender<PowerEvent>) -> Self {
+    Self { tx }
+  }
+}
+
+#[cfg(target_os = "macos")]
+impl EventHandler for StreamHandler {
+  fn sleep_query_deferred(&mut self, ack: SleepAck) {
+    let _ = self.tx.send(PowerEvent::SleepQuery(ack));
+  }
+
+  fn sleep_failed(&mut self) {
+    let _ = self.tx.send(PowerEvent::SleepFailed);
+  }
+
+  fn sleep_deferred(&mut self, ack: SleepAck) {
+    let _ = self.tx.send(PowerEvent::Sleep);
+    ack.allow();
+  }
+
+  fn wake(&mut self) {
+    let _ = self.tx.send(PowerEvent::Wake);
+  }
+}
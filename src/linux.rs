@@ -0,0 +1,442 @@
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+
+use std::{
+  ffi::{c_char, c_int, c_void, CStr, CString},
+  os::fd::{FromRawFd, OwnedFd, RawFd},
+  ptr::{null, null_mut},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Barrier, Mutex,
+  },
+  thread::JoinHandle,
+};
+
+use tracing::{debug, trace, warn};
+
+use crate::{Error, EventHandler};
+
+const INHIBIT_DISPATCH_TIMEOUT_MS: c_int = 250;
+
+pub struct Naptime {
+  stop: Arc<AtomicBool>,
+  thread: Option<JoinHandle<()>>,
+  // fds stashed by SleepAck::cancel instead of being leaked outright (see its doc comment); closed
+  // here on drop so a long-running process that gets denied repeatedly doesn't leak one fd per
+  // veto for its entire lifetime
+  leaked_inhibitors: Arc<Mutex<Vec<OwnedFd>>>,
+}
+
+impl Naptime {
+  pub fn new<E>(event_handler: E) -> Result<Self, Error>
+  where
+    E: EventHandler,
+  {
+    let event_handler = Box::new(event_handler);
+    let stop = Arc::new(AtomicBool::new(false));
+    let leaked_inhibitors = Arc::new(Mutex::new(Vec::new()));
+
+    let (tx, rx) = mpsc::channel();
+    let barrier = Arc::new(Barrier::new(2));
+    let thread_barrier = barrier.clone();
+    let thread_stop = stop.clone();
+    let thread_leaked_inhibitors = leaked_inhibitors.clone();
+    let thread = std::thread::spawn(move || {
+      dispatch_proc(event_handler, tx, thread_barrier, thread_stop, thread_leaked_inhibitors)
+    });
+
+    // wait for the thread to finish connecting to the bus and taking the initial delay inhibitor
+    rx.recv().unwrap()?;
+    barrier.wait();
+
+    Ok(Self {
+      stop,
+      thread: Some(thread),
+      leaked_inhibitors,
+    })
+  }
+
+  /// Prevents the system from sleeping (and, best-effort, from idling) due to user idleness, by
+  /// holding a logind `block`-mode inhibitor over `sleep:idle`. This is the same mechanism
+  /// `systemd-inhibit --what=sleep:idle` uses. The assertion is held until the returned
+  /// [`PowerAssertion`] is dropped.
+  pub fn prevent_idle_system_sleep(reason: &str) -> Result<PowerAssertion, Error> {
+    take_inhibitor("sleep:idle", "block", reason).map(PowerAssertion)
+  }
+
+  /// Prevents the display from sleeping due to user idleness, by holding a logind `block`-mode
+  /// inhibitor over `idle`. logind doesn't distinguish "display idle" from "system idle" the way
+  /// macOS's IOPM assertions do, so this is best-effort: whatever action the desktop environment has
+  /// configured for the idle timeout (blanking the screen, locking, or suspending) is what gets
+  /// blocked.
+  pub fn prevent_display_sleep(reason: &str) -> Result<PowerAssertion, Error> {
+    take_inhibitor("idle", "block", reason).map(PowerAssertion)
+  }
+}
+
+impl Drop for Naptime {
+  fn drop(&mut self) {
+    self.stop.store(true, Ordering::SeqCst);
+    if let Some(thread) = self.thread.take() {
+      thread.join().unwrap();
+    }
+
+    // close whatever delay inhibitor fds SleepAck::cancel stashed along the way; see
+    // leaked_inhibitors's field comment
+    let leaked = std::mem::take(&mut *self.leaked_inhibitors.lock().unwrap());
+    if !leaked.is_empty() {
+      debug!("closing {} inhibitor fd(s) held by cancelled sleep queries", leaked.len());
+    }
+  }
+}
+
+/// An RAII guard for a logind inhibitor lock. While held, it prevents the kind of sleep/idle it was
+/// taken for. Releasing it (by dropping it, which closes the underlying fd) lifts the restriction.
+pub struct PowerAssertion(OwnedFd);
+
+/// A deferred acknowledgment of a `PrepareForSleep(true)` signal, backed by logind's delay-mode
+/// inhibitor fd for the current sleep cycle. Dropping it without calling [`SleepAck::allow`] or
+/// [`SleepAck::cancel`] defaults to allowing the sleep (closing the fd), matching the other
+/// platforms. Unlike macOS, logind has no true veto: [`SleepAck::cancel`] can only hold the delay
+/// inhibitor as long as `systemd-logind.conf`'s `InhibitDelayMaxSec` allows (a few seconds by
+/// default) before the kernel suspends regardless, so it's documented here as best-effort.
+pub struct SleepAck {
+  fd: Option<OwnedFd>,
+  // where SleepAck::cancel stashes `fd` instead of leaking it outright; shared with the owning
+  // Naptime, which closes whatever accumulates here on drop
+  leaked_inhibitors: Arc<Mutex<Vec<OwnedFd>>>,
+}
+
+impl SleepAck {
+  fn new(fd: OwnedFd, leaked_inhibitors: Arc<Mutex<Vec<OwnedFd>>>) -> Self {
+    Self {
+      fd: Some(fd),
+      leaked_inhibitors,
+    }
+  }
+
+  /// Releases the delay inhibitor now, letting the suspend proceed immediately.
+  pub fn allow(mut self) {
+    drop(self.fd.take());
+  }
+
+  /// Best-effort veto: holds the delay inhibitor fd open instead of closing it, so the suspend is
+  /// held off for as long as logind's configured delay allows, rather than closing (and thus
+  /// releasing it) immediately. There is no logind API to truly cancel a sleep once logind decides
+  /// to proceed, so unlike macOS this can only delay, never deny outright. The fd isn't leaked for
+  /// the rest of the process's life: it's handed to the owning `Naptime`, which closes it (along
+  /// with any others accumulated the same way) when dropped.
+  pub fn cancel(mut self) {
+    if let Some(fd) = self.fd.take() {
+      self.leaked_inhibitors.lock().unwrap().push(fd);
+    }
+  }
+}
+
+impl Drop for SleepAck {
+  fn drop(&mut self) {
+    drop(self.fd.take());
+  }
+}
+
+fn dispatch_proc(
+  mut event_handler: Box<dyn EventHandler>,
+  tx: mpsc::Sender<Result<(), Error>>,
+  barrier: Arc<Barrier>,
+  stop: Arc<AtomicBool>,
+  leaked_inhibitors: Arc<Mutex<Vec<OwnedFd>>>,
+) {
+  let mut err = DBusError::new();
+  // SAFETY: err is a valid, initialized DBusError for the duration of this call
+  let conn = unsafe { dbus_bus_get(DBUS_BUS_SYSTEM, err.as_mut_ptr()) };
+  if conn.is_null() || err.is_set() {
+    tx.send(Err(Error(format!("dbus_bus_get failed: {}", err.message())))).unwrap();
+    return;
+  }
+
+  let mut delay_fd = match take_inhibitor("sleep", "delay", "naptime") {
+    Ok(fd) => fd,
+    Err(e) => {
+      tx.send(Err(e)).unwrap();
+      return;
+    }
+  };
+
+  let rule = CString::new("type='signal',interface='org.freedesktop.login1.Manager',member='PrepareForSleep'").unwrap();
+  // SAFETY: conn and the match rule string are both valid for the duration of this call
+  unsafe { dbus_bus_add_match(conn, rule.as_ptr(), err.as_mut_ptr()) };
+  if err.is_set() {
+    tx.send(Err(Error(format!("dbus_bus_add_match failed: {}", err.message())))).unwrap();
+    return;
+  }
+
+  tx.send(Ok(())).unwrap();
+  drop(tx);
+  barrier.wait();
+  drop(barrier);
+
+  let login1_manager_interface = CString::new("org.freedesktop.login1.Manager").unwrap();
+  let prepare_for_sleep_member = CString::new("PrepareForSleep").unwrap();
+
+  while !stop.load(Ordering::SeqCst) {
+    // SAFETY: conn is a valid, open connection
+    unsafe { dbus_connection_read_write_dispatch(conn, INHIBIT_DISPATCH_TIMEOUT_MS) };
+
+    loop {
+      // SAFETY: conn is a valid, open connection; dbus_connection_pop_message may return null when
+      // the incoming queue is empty
+      let msg = unsafe { dbus_connection_pop_message(conn) };
+      if msg.is_null() {
+        break;
+      }
+
+      // SAFETY: msg is a valid, non-null message we just popped and own until we unref it below
+      let is_prepare_for_sleep = unsafe {
+        dbus_message_is_signal(
+          msg,
+          login1_manager_interface.as_ptr(),
+          prepare_for_sleep_member.as_ptr(),
+        )
+      };
+
+      if is_prepare_for_sleep != 0 {
+        let mut about_to_sleep: dbus_bool_t = 0;
+        let mut arg_err = DBusError::new();
+        // SAFETY: msg is valid; DBUS_TYPE_INVALID terminates the varargs list
+        let ok = unsafe {
+          dbus_message_get_args(
+            msg,
+            arg_err.as_mut_ptr(),
+            DBUS_TYPE_BOOLEAN,
+            &mut about_to_sleep,
+            DBUS_TYPE_INVALID,
+          )
+        };
+        if ok != 0 {
+          if about_to_sleep != 0 {
+            trace!("PrepareForSleep(true)");
+            let ack = SleepAck::new(delay_fd, leaked_inhibitors.clone());
+            event_handler.sleep_query_deferred(ack);
+            // logind only sends one PrepareForSleep(true) signal, with no separate "sleep is
+            // definitely happening now" follow-up the way macOS's kIOMessageSystemWillSleep is
+            // separate from kIOMessageCanSystemSleep, so this is also the only place to notify
+            // sleep(). This runs right after sleep_query_deferred returns, not after its ack is
+            // actually resolved -- a handler that defers the ack onto a tokio task (per chunk0-2)
+            // may still be deciding when this fires. That's fine: sleep() is purely informational
+            // here, and a Deny is already best-effort and non-blocking (see SleepAck::cancel), so
+            // there's nothing worth waiting on before calling it.
+            event_handler.sleep();
+            // re-acquire a fresh delay inhibitor for the *next* cycle; the one we just handed to the
+            // ack now belongs to it (closed on allow or drop, stashed in leaked_inhibitors on cancel)
+            delay_fd = take_inhibitor("sleep", "delay", "naptime").unwrap_or_else(|e| {
+              warn!("failed to re-arm delay inhibitor: {e}");
+              // SAFETY: an invalid fd is never dereferenced; it only ever gets closed again, which is
+              // a harmless no-op
+              unsafe { OwnedFd::from_raw_fd(-1) }
+            });
+          } else {
+            trace!("PrepareForSleep(false)");
+            event_handler.wake();
+          }
+        } else {
+          warn!("PrepareForSleep signal had unexpected args: {}", arg_err.message());
+        }
+      } else {
+        debug!("unhandled message");
+      }
+
+      // SAFETY: msg is a valid, non-null message reference we own
+      unsafe { dbus_message_unref(msg) };
+    }
+  }
+
+  // SAFETY: conn is a valid, open connection we no longer need; this drops our reference to the
+  // shared bus connection
+  unsafe { dbus_connection_unref(conn) };
+
+  trace!("dispatch thread exiting");
+}
+
+/// Calls `org.freedesktop.login1.Manager.Inhibit(what, who, why, mode)` and returns the inhibitor fd.
+/// The lock is held until the returned fd is closed.
+fn take_inhibitor(what: &str, mode: &str, why: &str) -> Result<OwnedFd, Error> {
+  let mut err = DBusError::new();
+  // SAFETY: err is a valid, initialized DBusError for the duration of this call
+  let conn = unsafe { dbus_bus_get(DBUS_BUS_SYSTEM, err.as_mut_ptr()) };
+  if conn.is_null() || err.is_set() {
+    return Err(Error(format!("dbus_bus_get failed: {}", err.message())));
+  }
+
+  let destination = CString::new("org.freedesktop.login1").unwrap();
+  let path = CString::new("/org/freedesktop/login1").unwrap();
+  let interface = CString::new("org.freedesktop.login1.Manager").unwrap();
+  let method = CString::new("Inhibit").unwrap();
+
+  // SAFETY: all four strings outlive this call
+  let msg = unsafe {
+    dbus_message_new_method_call(
+      destination.as_ptr(),
+      path.as_ptr(),
+      interface.as_ptr(),
+      method.as_ptr(),
+    )
+  };
+  if msg.is_null() {
+    return Err(Error("dbus_message_new_method_call(Inhibit) failed".to_string()));
+  }
+
+  let what = CString::new(what).map_err(|e| Error(e.to_string()))?;
+  let who = CString::new("naptime").unwrap();
+  let why = CString::new(why).map_err(|e| Error(e.to_string()))?;
+  let mode = CString::new(mode).map_err(|e| Error(e.to_string()))?;
+
+  // SAFETY: msg is valid and owns no references to the CStrings beyond this call; DBUS_TYPE_INVALID
+  // terminates the varargs list
+  let ok = unsafe {
+    dbus_message_append_args(
+      msg,
+      DBUS_TYPE_STRING,
+      &what.as_ptr(),
+      DBUS_TYPE_STRING,
+      &who.as_ptr(),
+      DBUS_TYPE_STRING,
+      &why.as_ptr(),
+      DBUS_TYPE_STRING,
+      &mode.as_ptr(),
+      DBUS_TYPE_INVALID,
+    )
+  };
+  if ok == 0 {
+    unsafe { dbus_message_unref(msg) };
+    return Err(Error("dbus_message_append_args(Inhibit) failed".to_string()));
+  }
+
+  // SAFETY: conn and msg are both valid; msg stays ours to unref below, and the reply is owned
+  let reply = unsafe { dbus_connection_send_with_reply_and_block(conn, msg, -1, err.as_mut_ptr()) };
+  // SAFETY: conn came from dbus_bus_get, which hands out a new reference to the shared bus
+  // connection on every call; we're done with ours
+  unsafe { dbus_connection_unref(conn) };
+  unsafe { dbus_message_unref(msg) };
+  if reply.is_null() || err.is_set() {
+    return Err(Error(format!("Inhibit call failed: {}", err.message())));
+  }
+
+  let mut fd: RawFd = -1;
+  // SAFETY: reply is valid; DBUS_TYPE_INVALID terminates the varargs list
+  let ok = unsafe {
+    dbus_message_get_args(reply, err.as_mut_ptr(), DBUS_TYPE_UNIX_FD, &mut fd, DBUS_TYPE_INVALID)
+  };
+  unsafe { dbus_message_unref(reply) };
+  if ok == 0 {
+    return Err(Error(format!("Inhibit reply had unexpected args: {}", err.message())));
+  }
+
+  // SAFETY: fd was just handed to us by libdbus as a freshly dup'd descriptor we now own
+  Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+struct DBusError(DBusErrorRepr);
+
+impl DBusError {
+  fn new() -> Self {
+    let mut err = DBusErrorRepr {
+      _name: null(),
+      message: null(),
+      _dummy_bits: 0,
+      _padding1: null_mut(),
+    };
+    // SAFETY: err is a validly-shaped DBusError about to be initialized by the library itself
+    unsafe { dbus_error_init(&mut err) };
+    Self(err)
+  }
+
+  fn as_mut_ptr(&mut self) -> *mut DBusErrorRepr {
+    &mut self.0
+  }
+
+  fn is_set(&self) -> bool {
+    // SAFETY: self.0 was initialized by dbus_error_init and only ever mutated by libdbus calls that
+    // take `&mut self.0`
+    unsafe { dbus_error_is_set(&self.0) != 0 }
+  }
+
+  fn message(&self) -> String {
+    if self.0.message.is_null() {
+      return "(no error)".to_string();
+    }
+    // SAFETY: libdbus guarantees `message` is nul-terminated and valid for as long as the DBusError
+    // that owns it hasn't been freed
+    unsafe { CStr::from_ptr(self.0.message).to_string_lossy().into_owned() }
+  }
+}
+
+impl Drop for DBusError {
+  fn drop(&mut self) {
+    // SAFETY: self.0 was initialized by dbus_error_init
+    unsafe { dbus_error_free(&mut self.0) };
+  }
+}
+
+//
+// D-Bus (libdbus)
+//
+
+type dbus_bool_t = u32;
+
+#[repr(C)]
+struct DBusConnectionOpaque(c_void);
+type DBusConnection = DBusConnectionOpaque;
+
+#[repr(C)]
+struct DBusMessageOpaque(c_void);
+type DBusMessage = DBusMessageOpaque;
+
+/// Mirrors the public, stable layout of libdbus's `DBusError` (`dbus/dbus-errors.h`): `name` and
+/// `message` are part of its documented ABI, `message` is what we actually read.
+#[repr(C)]
+struct DBusErrorRepr {
+  _name: *const c_char,
+  message: *const c_char,
+  _dummy_bits: u32,
+  _padding1: *mut c_void,
+}
+
+const DBUS_BUS_SYSTEM: c_int = 1;
+
+const DBUS_TYPE_INVALID: c_int = 0;
+const DBUS_TYPE_STRING: c_int = b's' as c_int;
+const DBUS_TYPE_BOOLEAN: c_int = b'b' as c_int;
+const DBUS_TYPE_UNIX_FD: c_int = b'h' as c_int;
+
+#[link(name = "dbus-1")]
+extern "C" {
+  fn dbus_error_init(error: *mut DBusErrorRepr);
+  fn dbus_error_free(error: *mut DBusErrorRepr);
+  fn dbus_error_is_set(error: *const DBusErrorRepr) -> dbus_bool_t;
+
+  fn dbus_bus_get(bus_type: c_int, error: *mut DBusErrorRepr) -> *mut DBusConnection;
+  fn dbus_bus_add_match(conn: *mut DBusConnection, rule: *const c_char, error: *mut DBusErrorRepr);
+
+  fn dbus_connection_read_write_dispatch(conn: *mut DBusConnection, timeout_ms: c_int) -> dbus_bool_t;
+  fn dbus_connection_pop_message(conn: *mut DBusConnection) -> *mut DBusMessage;
+  fn dbus_connection_send_with_reply_and_block(
+    conn: *mut DBusConnection,
+    message: *mut DBusMessage,
+    timeout_ms: c_int,
+    error: *mut DBusErrorRepr,
+  ) -> *mut DBusMessage;
+  fn dbus_connection_unref(conn: *mut DBusConnection);
+
+  fn dbus_message_new_method_call(
+    destination: *const c_char,
+    path: *const c_char,
+    interface: *const c_char,
+    method: *const c_char,
+  ) -> *mut DBusMessage;
+  fn dbus_message_is_signal(message: *mut DBusMessage, interface: *const c_char, signal_name: *const c_char) -> dbus_bool_t;
+  fn dbus_message_unref(message: *mut DBusMessage);
+
+  fn dbus_message_append_args(message: *mut DBusMessage, first_arg_type: c_int, ...) -> dbus_bool_t;
+  fn dbus_message_get_args(message: *mut DBusMessage, error: *mut DBusErrorRepr, first_arg_type: c_int, ...) -> dbus_bool_t;
+}
@@ -3,16 +3,17 @@
 #![allow(non_upper_case_globals)]
 
 use std::{
-  ffi::{c_int, c_void},
+  ffi::{c_char, c_int, c_void, CString},
   marker::{PhantomData, PhantomPinned},
   ptr::null_mut,
-  sync::{mpsc, Arc, Barrier},
+  sync::{mpsc, Arc, Barrier, Mutex},
   thread::JoinHandle,
+  time::Duration,
 };
 
 use tracing::{debug, trace, warn};
 
-use crate::{Error, EventHandler, SleepQueryResponse};
+use crate::{Error, EventHandler};
 
 pub struct Naptime {
   // The run loop id so we can ask it to stop
@@ -20,10 +21,34 @@ pub struct Naptime {
 
   // We create a new macos run loop on a new thread so we can receive the power events
   run_loop_thread: Option<JoinHandle<()>>,
+
+  // Device-matching notifications registered via watch_matching(). Handed off to the run-loop
+  // thread on drop rather than torn down here, so teardown happens after CFRunLoopRun() returns
+  // instead of racing that thread's device_matched_callback/device_terminated_callback.
+  device_watchers: Vec<DeviceWatcherHandle>,
+  device_watchers_tx: mpsc::Sender<Vec<DeviceWatcherHandle>>,
+}
+
+/// Tuning knobs for [`Naptime::new_with_config`]. Defaults match the behavior of [`Naptime::new`].
+#[derive(Default)]
+pub struct NaptimeConfig {
+  /// Machines can emit `kIOMessageSystemHasPoweredOn` in quick bursts (display wake, lid open, and
+  /// external display reattachment can each fire their own notification within the same wake-up).
+  /// When set, wake notifications are coalesced on a `CFRunLoopTimer`: each one pushes the fire date
+  /// `wake_debounce` further out, and [`EventHandler::wake`] only runs once the storm settles. When
+  /// `None` (the default), every `kIOMessageSystemHasPoweredOn` invokes `wake()` immediately.
+  pub wake_debounce: Option<Duration>,
 }
 
 impl Naptime {
   pub fn new<E>(event_handler: E) -> Result<Self, Error>
+  where
+    E: EventHandler,
+  {
+    Self::new_with_config(event_handler, NaptimeConfig::default())
+  }
+
+  pub fn new_with_config<E>(event_handler: E, config: NaptimeConfig) -> Result<Self, Error>
   where
     E: EventHandler,
   {
@@ -31,10 +56,12 @@ impl Naptime {
 
     // spawn the thread that will subscribe to the power events
     let (tx, rx) = mpsc::channel();
+    let (device_watchers_tx, device_watchers_rx) = mpsc::channel();
     let barrier = Arc::new(Barrier::new(2));
     let thread_barrier = barrier.clone();
-    let run_loop_thread =
-      std::thread::spawn(move || run_loop_proc(event_handler, tx, thread_barrier));
+    let run_loop_thread = std::thread::spawn(move || {
+      run_loop_proc(event_handler, config.wake_debounce, tx, device_watchers_rx, thread_barrier)
+    });
 
     // wait for the thread to finish initializing
     let run_loop = rx.recv().unwrap()?;
@@ -47,8 +74,414 @@ impl Naptime {
     Ok(Self {
       run_loop: Some(run_loop),
       run_loop_thread: Some(run_loop_thread),
+      device_watchers: Vec::new(),
+      device_watchers_tx,
     })
   }
+
+  /// Prevents the system from sleeping due to user idleness (the display may still sleep). This is
+  /// the same mechanism `caffeinate -i` and CUPS use to keep a machine awake while it has real work
+  /// to do. The assertion is held until the returned [`PowerAssertion`] is dropped.
+  pub fn prevent_idle_system_sleep(reason: &str) -> Result<PowerAssertion, Error> {
+    // SAFETY: kIOPMAssertionTypePreventUserIdleSystemSleep is a valid, static CFString
+    unsafe { create_power_assertion(kIOPMAssertionTypePreventUserIdleSystemSleep, reason) }
+  }
+
+  /// Prevents the display from sleeping due to user idleness. The system may still go to sleep on
+  /// its own unless [`Naptime::prevent_idle_system_sleep`] is also held. The assertion is held until
+  /// the returned [`PowerAssertion`] is dropped.
+  pub fn prevent_display_sleep(reason: &str) -> Result<PowerAssertion, Error> {
+    // SAFETY: kIOPMAssertionTypePreventUserIdleDisplaySleep is a valid, static CFString
+    unsafe { create_power_assertion(kIOPMAssertionTypePreventUserIdleDisplaySleep, reason) }
+  }
+
+  /// An alternative to implementing [`EventHandler`]: returns a `Naptime` alongside a
+  /// `futures::Stream` of [`PowerEvent`](crate::PowerEvent)s, so events can be consumed with
+  /// `stream.next().await` from ordinary async code instead of a blocking callback on the run-loop
+  /// thread.
+  pub fn event_stream() -> Result<(Self, crate::EventStream), Error> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let naptime = Self::new(crate::StreamHandler::new(tx))?;
+    Ok((naptime, crate::EventStream::new(rx)))
+  }
+
+  /// Watches for IOKit devices matching `service_class` (e.g. `"IOUSBDevice"`), optionally narrowed
+  /// to a specific USB `idVendor`/`idProduct`, multiplexing the notifications onto this `Naptime`'s
+  /// existing run loop rather than spinning up a second one. Devices already present at call time
+  /// are drained through the iterator immediately, so `handler.device_added` fires once for them
+  /// too, the same as for devices that show up later.
+  pub fn watch_matching<H>(
+    &mut self,
+    service_class: &str,
+    id_vendor: Option<u16>,
+    id_product: Option<u16>,
+    handler: H,
+  ) -> Result<(), Error>
+  where
+    H: DeviceEventHandler,
+  {
+    let run_loop = match &self.run_loop {
+      Some(run_loop) => run_loop.0,
+      None => return Err(Error("Naptime's run loop has already stopped".to_string())),
+    };
+
+    // SAFETY: kIOMasterPortDefault is a valid constant exported by IOKit
+    let notify_port = unsafe { IONotificationPortCreate(kIOMasterPortDefault) };
+    if notify_port.is_null() {
+      return Err(Error("IONotificationPortCreate failed".to_string()));
+    }
+    // SAFETY: notify_port and run_loop are both valid; this is the same pattern run_loop_proc uses
+    // to wire up the power-event notification port
+    unsafe { CFRunLoopAddSource(run_loop, IONotificationPortGetRunLoopSource(notify_port), kCFRunLoopCommonModes) };
+
+    let state = Arc::new(DeviceWatcherState {
+      handler: Mutex::new(Box::new(handler)),
+    });
+
+    let registration = match register_matching_notifications(notify_port, service_class, id_vendor, id_product, &state) {
+      Ok(registration) => registration,
+      Err(e) => {
+        // SAFETY: the source was added to run_loop above and nothing else references notify_port yet
+        unsafe {
+          CFRunLoopRemoveSource(run_loop, IONotificationPortGetRunLoopSource(notify_port), kCFRunLoopCommonModes);
+          IONotificationPortDestroy(notify_port);
+        }
+        return Err(e);
+      }
+    };
+
+    self.device_watchers.push(DeviceWatcherHandle {
+      run_loop,
+      notify_port,
+      added_iter: registration.added_iter,
+      removed_iter: registration.removed_iter,
+      added_refcon: registration.added_refcon,
+      removed_refcon: registration.removed_refcon,
+    });
+    Ok(())
+  }
+}
+
+/// An observer for device hotplug events registered via [`Naptime::watch_matching`]. Unlike
+/// [`EventHandler`], devices already present when the watch starts also fire `device_added` once, so
+/// callers don't need a separate enumeration pass to learn about them.
+pub trait DeviceEventHandler: Send + 'static {
+  /// Called for every device that matches, both ones already present when the watch started and
+  /// ones that show up afterward. `service` is the matched `io_service_t`, released automatically
+  /// after this call returns; retain it with `IOObjectRetain` if it needs to outlive the callback.
+  fn device_added(&mut self, _service: io_service_t) {}
+
+  /// Called when a previously-matched device goes away. Same `service` lifetime rules as
+  /// [`DeviceEventHandler::device_added`].
+  fn device_removed(&mut self, _service: io_service_t) {}
+}
+
+struct DeviceWatcherState {
+  handler: Mutex<Box<dyn DeviceEventHandler>>,
+}
+
+struct DeviceWatcherHandle {
+  // the run loop the notification source was added to in watch_matching, so drop can remove it
+  run_loop: CFRunLoopRef,
+  notify_port: IONotificationPortRef,
+  added_iter: io_iterator_t,
+  removed_iter: io_iterator_t,
+  // the Arc<DeviceWatcherState> reference handed to each of the two registered callbacks; reclaimed
+  // on drop once notify_port is torn down and neither callback can fire again
+  added_refcon: *mut c_void,
+  removed_refcon: *mut c_void,
+}
+
+// SAFETY: a DeviceWatcherHandle holds no thread-affine state of its own; Naptime::drop hands its
+// device watchers off to the run-loop thread over a channel so teardown happens there, after
+// CFRunLoopRun() returns, instead of racing the callbacks this handle's port can still dispatch
+unsafe impl Send for DeviceWatcherHandle {}
+
+impl Drop for DeviceWatcherHandle {
+  fn drop(&mut self) {
+    // SAFETY: run_loop and notify_port are the same pair passed to CFRunLoopAddSource in
+    // watch_matching; mirrors the cleanup watch_matching's own error path does on failure
+    unsafe {
+      CFRunLoopRemoveSource(self.run_loop, IONotificationPortGetRunLoopSource(self.notify_port), kCFRunLoopCommonModes);
+    }
+
+    // SAFETY: added_refcon was created by Arc::into_raw in register_matching_notifications and is
+    // still valid; this runs on the run-loop thread after CFRunLoopRun() has returned, so neither
+    // device_matched_callback nor device_terminated_callback can be running concurrently
+    let state = unsafe { Arc::from_raw(self.added_refcon as *const DeviceWatcherState) };
+    // A device can match or terminate between the last time a callback drained its iterator and
+    // this teardown; drain both to exhaustion first so those io_service_t references (retained by
+    // IOIteratorNext) don't leak once the iterators themselves are released below.
+    drain_iterator(&state, self.added_iter, true);
+    drain_iterator(&state, self.removed_iter, false);
+    drop(state);
+
+    // SAFETY: added_iter/removed_iter were returned by IOServiceAddMatchingNotification and are now
+    // fully drained; notify_port is the same one they were registered against
+    unsafe {
+      IOObjectRelease(self.added_iter);
+      IOObjectRelease(self.removed_iter);
+      IONotificationPortDestroy(self.notify_port);
+    }
+    // SAFETY: notify_port is torn down above, so neither callback can be invoked again; it's now
+    // safe to reclaim the other Arc reference it was holding
+    unsafe {
+      drop(Arc::from_raw(self.removed_refcon as *const DeviceWatcherState));
+    }
+  }
+}
+
+struct DeviceMatchingRegistration {
+  added_iter: io_iterator_t,
+  removed_iter: io_iterator_t,
+  added_refcon: *mut c_void,
+  removed_refcon: *mut c_void,
+}
+
+/// Builds the matching dictionaries, registers both notification types, and drains each iterator
+/// once up front so already-present devices fire and the notification is armed for future matches.
+/// `IOServiceAddMatchingNotification` takes ownership of the dictionary it's given, so the "added"
+/// and "removed" registrations each need their own reference.
+fn register_matching_notifications(
+  notify_port: IONotificationPortRef,
+  service_class: &str,
+  id_vendor: Option<u16>,
+  id_product: Option<u16>,
+  state: &Arc<DeviceWatcherState>,
+) -> Result<DeviceMatchingRegistration, Error> {
+  let service_class = CString::new(service_class).map_err(|e| Error(e.to_string()))?;
+  // SAFETY: service_class is a valid, nul-terminated string for the duration of this call
+  let matching_added = unsafe { IOServiceMatching(service_class.as_ptr()) };
+  if matching_added.is_null() {
+    return Err(Error("IOServiceMatching failed".to_string()));
+  }
+  // SAFETY: matching_added was just created above and is non-null
+  let matching_removed = unsafe { CFRetain(matching_added as CFTypeRef) as CFMutableDictionaryRef };
+
+  if let Some(id_vendor) = id_vendor {
+    set_matching_dict_number(matching_added, "idVendor", id_vendor)?;
+    set_matching_dict_number(matching_removed, "idVendor", id_vendor)?;
+  }
+  if let Some(id_product) = id_product {
+    set_matching_dict_number(matching_added, "idProduct", id_product)?;
+    set_matching_dict_number(matching_removed, "idProduct", id_product)?;
+  }
+
+  let added_refcon = Arc::into_raw(state.clone()) as *mut c_void;
+  let mut added_iter: io_iterator_t = 0;
+  let notification_type = CString::new("IOServiceMatched").unwrap();
+  // SAFETY: notify_port, matching_added, and added_refcon are all valid; this call consumes one
+  // reference to matching_added
+  let ret = unsafe {
+    IOServiceAddMatchingNotification(
+      notify_port,
+      notification_type.as_ptr(),
+      matching_added,
+      device_matched_callback,
+      added_refcon,
+      &mut added_iter,
+    )
+  };
+  if ret != kIOReturnSuccess {
+    // SAFETY: added_refcon was created by Arc::into_raw above and never handed to a successful
+    // registration
+    unsafe { drop(Arc::from_raw(added_refcon as *const DeviceWatcherState)) };
+    // SAFETY: matching_removed still holds the extra reference taken above
+    unsafe { CFRelease(matching_removed as CFTypeRef) };
+    return Err(Error(format!("IOServiceAddMatchingNotification(matched) failed. ret={:08x}", ret)));
+  }
+  // drain already-matched devices and arm the notification for future ones
+  device_matched_callback(added_refcon, added_iter);
+
+  let removed_refcon = Arc::into_raw(state.clone()) as *mut c_void;
+  let mut removed_iter: io_iterator_t = 0;
+  let notification_type = CString::new("IOServiceTerminate").unwrap();
+  // SAFETY: notify_port, matching_removed, and removed_refcon are all valid; this call consumes the
+  // extra reference to matching_removed taken above
+  let ret = unsafe {
+    IOServiceAddMatchingNotification(
+      notify_port,
+      notification_type.as_ptr(),
+      matching_removed,
+      device_terminated_callback,
+      removed_refcon,
+      &mut removed_iter,
+    )
+  };
+  if ret != kIOReturnSuccess {
+    // SAFETY: removed_refcon was created by Arc::into_raw above and never handed to a successful
+    // registration
+    unsafe { drop(Arc::from_raw(removed_refcon as *const DeviceWatcherState)) };
+    // SAFETY: added_iter was successfully registered above and hasn't been released yet
+    unsafe { IOObjectRelease(added_iter) };
+    // SAFETY: added_refcon was handed to the successful "added" registration above, which now owns it
+    unsafe { drop(Arc::from_raw(added_refcon as *const DeviceWatcherState)) };
+    return Err(Error(format!(
+      "IOServiceAddMatchingNotification(terminated) failed. ret={:08x}",
+      ret
+    )));
+  }
+  // drain already-present terminations (there shouldn't be any, but the docs say to drain regardless
+  // to arm the notification) and arm it for future ones
+  device_terminated_callback(removed_refcon, removed_iter);
+
+  Ok(DeviceMatchingRegistration {
+    added_iter,
+    removed_iter,
+    added_refcon,
+    removed_refcon,
+  })
+}
+
+fn set_matching_dict_number(dict: CFMutableDictionaryRef, key: &str, value: u16) -> Result<(), Error> {
+  let key_cfstr = create_cfstring(key)?;
+  let value: i32 = value as i32;
+  // SAFETY: value lives for the duration of this call, which is all CFNumberCreate needs
+  let number = unsafe { CFNumberCreate(null_mut(), kCFNumberSInt32Type, &value as *const i32 as *const c_void) };
+  // SAFETY: dict, key_cfstr, and number are all valid CF objects; CFDictionarySetValue retains both
+  unsafe { CFDictionarySetValue(dict, key_cfstr as *const c_void, number as *const c_void) };
+  // SAFETY: CFDictionarySetValue above retained its own references, so ours can be released
+  unsafe {
+    CFRelease(key_cfstr as CFTypeRef);
+    CFRelease(number as CFTypeRef);
+  }
+  Ok(())
+}
+
+extern "C" fn device_matched_callback(refCon: *mut c_void, iterator: io_iterator_t) {
+  // SAFETY: refCon is an `Arc<DeviceWatcherState>` pointer created by `Arc::into_raw` in
+  // register_matching_notifications, kept alive for the life of the registration
+  let state = unsafe { Arc::from_raw(refCon as *const DeviceWatcherState) };
+  drain_iterator(&state, iterator, true);
+  // this callback fires again for future matches, so keep the Arc's reference count intact
+  std::mem::forget(state);
+}
+
+extern "C" fn device_terminated_callback(refCon: *mut c_void, iterator: io_iterator_t) {
+  // SAFETY: refCon is an `Arc<DeviceWatcherState>` pointer created by `Arc::into_raw` in
+  // register_matching_notifications, kept alive for the life of the registration
+  let state = unsafe { Arc::from_raw(refCon as *const DeviceWatcherState) };
+  drain_iterator(&state, iterator, false);
+  // this callback fires again for future terminations, so keep the Arc's reference count intact
+  std::mem::forget(state);
+}
+
+fn drain_iterator(state: &DeviceWatcherState, iterator: io_iterator_t, added: bool) {
+  let mut handler = state.handler.lock().unwrap();
+  loop {
+    // SAFETY: iterator is a valid io_iterator_t returned by IOServiceAddMatchingNotification
+    let service = unsafe { IOIteratorNext(iterator) };
+    if service == 0 {
+      break;
+    }
+    if added {
+      handler.device_added(service);
+    } else {
+      handler.device_removed(service);
+    }
+    // SAFETY: service is a valid io_object_t that IOIteratorNext retained on our behalf; we're done
+    // with it once the handler callback returns
+    unsafe { IOObjectRelease(service) };
+  }
+}
+
+/// An RAII guard for an `IOPMAssertion`. While held, it prevents the kind of sleep it was created
+/// for. Releasing it (by dropping it) lifts the restriction.
+pub struct PowerAssertion(IOPMAssertionID);
+
+// SAFETY: IOPMAssertionRelease is documented as thread-safe, so the assertion ID may be released
+// from any thread, not just the one that created it.
+unsafe impl Send for PowerAssertion {}
+
+impl Drop for PowerAssertion {
+  fn drop(&mut self) {
+    // SAFETY: self.0 is a valid assertion ID returned by a prior IOPMAssertionCreateWithName call
+    let ret = unsafe { IOPMAssertionRelease(self.0) };
+    if ret != kIOReturnSuccess {
+      warn!("IOPMAssertionRelease failed. ret={:08x}", ret);
+    }
+  }
+}
+
+/// SAFETY: `assertion_type` must be a valid, non-null CFString naming an IOPM assertion type.
+unsafe fn create_power_assertion(assertion_type: CFStringRef, reason: &str) -> Result<PowerAssertion, Error> {
+  let reason = create_cfstring(reason)?;
+  let mut assertion_id: IOPMAssertionID = 0;
+  let ret = IOPMAssertionCreateWithName(assertion_type, kIOPMAssertionLevelOn, reason, &mut assertion_id);
+  CFRelease(reason as CFTypeRef);
+  if ret != kIOReturnSuccess {
+    return Err(Error(format!("IOPMAssertionCreateWithName failed. ret={:08x}", ret)));
+  }
+  Ok(PowerAssertion(assertion_id))
+}
+
+/// Builds a CFString from a Rust string. The caller owns the returned reference and must `CFRelease`
+/// it when done.
+fn create_cfstring(s: &str) -> Result<CFStringRef, Error> {
+  let cstr = CString::new(s).map_err(|e| Error(e.to_string()))?;
+  // SAFETY: cstr is a valid, nul-terminated C string for the duration of this call
+  let cfstr = unsafe { CFStringCreateWithCString(null_mut(), cstr.as_ptr(), kCFStringEncodingUTF8) };
+  if cfstr.is_null() {
+    return Err(Error("CFStringCreateWithCString failed".to_string()));
+  }
+  Ok(cfstr)
+}
+
+/// A deferred acknowledgment of a `kIOMessageCanSystemSleep` or `kIOMessageSystemWillSleep`
+/// notification. The OS gives us 30 seconds from the moment this token is handed out to call
+/// [`SleepAck::allow`] or [`SleepAck::cancel`], which makes it safe to move onto another thread (e.g.
+/// a tokio task) to finish async work before acknowledging. If dropped without being used, it
+/// defaults to allowing the sleep.
+pub struct SleepAck {
+  root_port: io_connect_t,
+  notification_id: *mut c_void,
+}
+
+// SAFETY: IOAllowPowerChange/IOCancelPowerChange are documented as thread-safe, so the token may be
+// acknowledged from any thread, not just the one that received the notification.
+unsafe impl Send for SleepAck {}
+
+impl SleepAck {
+  fn new(root_port: io_connect_t, notification_id: *mut c_void) -> Self {
+    Self {
+      root_port,
+      notification_id,
+    }
+  }
+
+  /// Tells the OS it's fine to proceed with the sleep.
+  pub fn allow(self) {
+    // SAFETY: root_port and notification_id come from the IORegisterForSystemPower callback that
+    // produced this token
+    let ret = unsafe { IOAllowPowerChange(self.root_port, self.notification_id) };
+    if ret != kIOReturnSuccess {
+      warn!("IOAllowPowerChange failed. ret={:08x}", ret);
+    }
+    std::mem::forget(self);
+  }
+
+  /// Vetoes the sleep. Only meaningful in response to `kIOMessageCanSystemSleep`; the OS ignores
+  /// this for `kIOMessageSystemWillSleep` since sleep is no longer avoidable by that point.
+  pub fn cancel(self) {
+    // SAFETY: root_port and notification_id come from the IORegisterForSystemPower callback that
+    // produced this token
+    let ret = unsafe { IOCancelPowerChange(self.root_port, self.notification_id) };
+    if ret != kIOReturnSuccess {
+      warn!("IOCancelPowerChange failed. ret={:08x}", ret);
+    }
+    std::mem::forget(self);
+  }
+}
+
+impl Drop for SleepAck {
+  fn drop(&mut self) {
+    // SAFETY: root_port and notification_id come from the IORegisterForSystemPower callback that
+    // produced this token
+    let ret = unsafe { IOAllowPowerChange(self.root_port, self.notification_id) };
+    if ret != kIOReturnSuccess {
+      warn!("IOAllowPowerChange failed. ret={:08x}", ret);
+    }
+  }
 }
 
 // SAFETY: Once the listener thread starts, we want to be able to stop it. This is a pointer to the
@@ -61,11 +494,19 @@ unsafe impl Sync for CFRunLoopRefWrapper {}
 struct ThreadState {
   event_handler: Box<dyn EventHandler>,
   root_port: io_connect_t,
+  run_loop: CFRunLoopRef,
+  wake_debounce: Option<Duration>,
+  // created lazily on the first kIOMessageSystemHasPoweredOn so idle sessions that never wake don't
+  // pay for a timer they never use
+  wake_timer: Option<CFRunLoopTimerRef>,
+  wake_pending: bool,
 }
 
 fn run_loop_proc(
   event_handler: Box<dyn EventHandler>,
+  wake_debounce: Option<Duration>,
   tx: mpsc::Sender<Result<CFRunLoopRefWrapper, Error>>,
+  device_watchers_rx: mpsc::Receiver<Vec<DeviceWatcherHandle>>,
   barrier: Arc<Barrier>,
 ) {
   // capture this thread's run loop
@@ -81,6 +522,10 @@ fn run_loop_proc(
   let mut state = Box::new(ThreadState {
     event_handler,
     root_port: 0,
+    run_loop,
+    wake_debounce,
+    wake_timer: None,
+    wake_pending: false,
   });
   let state_ptr = &mut *state as *mut ThreadState;
 
@@ -141,6 +586,19 @@ fn run_loop_proc(
     IOServiceClose(root_port);
     IONotificationPortDestroy(notify_port_ref);
 
+    // Naptime::drop sends its device watchers over before calling CFRunLoopStop, so they're always
+    // waiting here by the time CFRunLoopRun() returns; tear them down now, on this thread, so their
+    // own cleanup can't race device_matched_callback/device_terminated_callback, which only ever run
+    // while this same run loop is actually spinning
+    drop(device_watchers_rx.recv().unwrap_or_default());
+
+    // the debounce timer, if one was ever created, holds a reference to `run_loop` via its common
+    // mode registration, so it must be torn down before we release our own reference below
+    if let Some(timer) = state.wake_timer.take() {
+      CFRunLoopTimerInvalidate(timer);
+      CFRelease(timer as CFTypeRef);
+    }
+
     // release the run loop
     CFRelease(run_loop as *const c_void);
   }
@@ -168,22 +626,8 @@ extern "C" fn system_power_event_handler(
     // See: https://developer.apple.com/documentation/iokit/1557114-ioregisterforsystempower?language=objc
     kIOMessageCanSystemSleep => {
       trace!("kIOMessageCanSystemSleep");
-
-      let response = state.event_handler.sleep_query();
-      match response {
-        SleepQueryResponse::Allow => {
-          let ret = unsafe { IOAllowPowerChange(state.root_port, messageArgument) };
-          if ret != kIOReturnSuccess {
-            warn!("IOAllowPowerChange failed. ret={:08x}", ret);
-          }
-        }
-        SleepQueryResponse::Deny => {
-          let ret = unsafe { IOCancelPowerChange(state.root_port, messageArgument) };
-          if ret != kIOReturnSuccess {
-            warn!("IOCancelPowerChange failed. ret={:08x}", ret);
-          }
-        }
-      }
+      let ack = SleepAck::new(state.root_port, messageArgument);
+      state.event_handler.sleep_query_deferred(ack);
     }
 
     // This is a notification that the system is definitely going to sleep. We are required to
@@ -192,11 +636,8 @@ extern "C" fn system_power_event_handler(
     // See: https://developer.apple.com/documentation/iokit/1557114-ioregisterforsystempower?language=objc
     kIOMessageSystemWillSleep => {
       trace!("kIOMessageSystemWillSleep");
-      state.event_handler.sleep();
-      let ret = unsafe { IOAllowPowerChange(state.root_port, messageArgument) };
-      if ret != kIOReturnSuccess {
-        warn!("IOAllowPowerChange failed. ret={:08x}", ret);
-      }
+      let ack = SleepAck::new(state.root_port, messageArgument);
+      state.event_handler.sleep_deferred(ack);
     }
     kIOMessageSystemWillNotSleep => {
       trace!("kIOMessageSystemWillNotSleep");
@@ -207,7 +648,10 @@ extern "C" fn system_power_event_handler(
     }
     kIOMessageSystemHasPoweredOn => {
       trace!("kIOMessageSystemHasPoweredOn");
-      state.event_handler.wake();
+      match state.wake_debounce {
+        Some(debounce) => arm_wake_debounce_timer(&mut state, debounce),
+        None => state.event_handler.wake(),
+      }
     }
     _ => {
       debug!("unknown message type");
@@ -218,8 +662,77 @@ extern "C" fn system_power_event_handler(
   Box::leak(state);
 }
 
+/// Pushes the debounce timer's fire date `debounce` seconds into the future, creating the timer
+/// lazily on the first call. Only when the timer actually fires (i.e. no further wake notifications
+/// arrived within `debounce`) does `event_handler.wake()` run.
+fn arm_wake_debounce_timer(state: &mut ThreadState, debounce: Duration) {
+  state.wake_pending = true;
+
+  // SAFETY: CFAbsoluteTimeGetCurrent has no preconditions
+  let fire_date = unsafe { CFAbsoluteTimeGetCurrent() } + debounce.as_secs_f64();
+
+  // A non-repeating CFRunLoopTimer (interval 0.0) auto-invalidates itself the instant it fires, so
+  // a timer from a previous storm can't just be re-armed with CFRunLoopTimerSetNextFireDate once
+  // it's fired once; it has to be recreated.
+  if let Some(timer) = state.wake_timer {
+    // SAFETY: timer was created by a prior CFRunLoopTimerCreate call in this same function
+    let is_valid = unsafe { CFRunLoopTimerIsValid(timer) } != 0;
+    if is_valid {
+      // SAFETY: timer is still valid and registered on the run loop
+      unsafe { CFRunLoopTimerSetNextFireDate(timer, fire_date) };
+      return;
+    }
+    // SAFETY: an invalidated timer is no longer on the run loop and holds no other references
+    unsafe { CFRelease(timer as CFTypeRef) };
+    state.wake_timer = None;
+  }
+
+  let mut context = CFRunLoopTimerContext {
+    version: 0,
+    info: state as *mut ThreadState as *mut c_void,
+    retain: null_mut(),
+    release: null_mut(),
+    copyDescription: null_mut(),
+  };
+  // SAFETY: context is valid for the duration of this call; CFRunLoopTimerCreate copies what it
+  // needs out of it
+  let timer = unsafe {
+    CFRunLoopTimerCreate(
+      null_mut(),
+      fire_date,
+      0.0,
+      0,
+      0,
+      wake_debounce_timer_fired,
+      &mut context,
+    )
+  };
+  // SAFETY: state.run_loop is retained for the lifetime of the run loop thread
+  unsafe { CFRunLoopAddTimer(state.run_loop, timer, kCFRunLoopCommonModes) };
+  state.wake_timer = Some(timer);
+}
+
+extern "C" fn wake_debounce_timer_fired(_timer: CFRunLoopTimerRef, info: *mut c_void) {
+  // SAFETY: info is the ThreadState pointer set up in arm_wake_debounce_timer, which outlives the
+  // timer since the timer is invalidated before run_loop_proc drops its ThreadState
+  let state = unsafe { &mut *(info as *mut ThreadState) };
+  if state.wake_pending {
+    state.wake_pending = false;
+    trace!("debounced wake");
+    state.event_handler.wake();
+  }
+}
+
 impl Drop for Naptime {
   fn drop(&mut self) {
+    // Hand device watchers off to the run-loop thread rather than tearing them down here: their
+    // cleanup (draining iterators, destroying the notification port) must happen after
+    // CFRunLoopRun() returns, or it can race that thread still dispatching
+    // device_matched_callback/device_terminated_callback for the very port being destroyed. The
+    // send below happens-before the CFRunLoopStop call further down, so run_loop_proc's matching
+    // recv is guaranteed to see it once its CFRunLoopRun() returns.
+    let _ = self.device_watchers_tx.send(std::mem::take(&mut self.device_watchers));
+
     // tell the thread to stop
     // SAFETY: this is where we release our interest in the run loop
     if let Some(CFRunLoopRefWrapper(run_loop)) = self.run_loop.take() {
@@ -247,19 +760,26 @@ type natural_t = u32;
 type mach_port_t = natural_t;
 type io_object_t = mach_port_t;
 type io_connect_t = io_object_t;
-type io_service_t = io_object_t;
+/// The raw IOKit registry object handle handed to [`DeviceEventHandler`] callbacks.
+pub type io_service_t = io_object_t;
+type io_iterator_t = io_object_t;
 type kern_return_t = c_int;
 
 //
 // Core Foundation
 //
 
+type Boolean = u8;
+
 type CFTypeRef = *const c_void;
 
 #[repr(C)]
 struct __CFString(c_void);
 type CFStringRef = *const __CFString;
 
+type CFStringEncoding = u32;
+const kCFStringEncodingUTF8: CFStringEncoding = 0x0800_0100;
+
 #[repr(C)]
 struct __CFRunLoop {
   _data: [u8; 0],
@@ -276,6 +796,39 @@ type CFRunLoopSourceRef = *mut __CFRunLoopSource;
 
 type CFRunLoopMode = CFStringRef;
 
+type CFIndex = isize;
+type CFOptionFlags = u64;
+type CFTimeInterval = f64;
+type CFAbsoluteTime = CFTimeInterval;
+
+#[repr(C)]
+struct __CFRunLoopTimer {
+  _data: [u8; 0],
+  _marker: PhantomData<(*mut u8, PhantomPinned)>,
+}
+type CFRunLoopTimerRef = *mut __CFRunLoopTimer;
+type CFRunLoopTimerCallBack = extern "C" fn(timer: CFRunLoopTimerRef, info: *mut c_void);
+
+#[repr(C)]
+struct CFRunLoopTimerContext {
+  version: CFIndex,
+  info: *mut c_void,
+  retain: *const c_void,
+  release: *const c_void,
+  copyDescription: *const c_void,
+}
+
+#[repr(C)]
+struct __CFDictionary(c_void);
+type CFDictionaryRef = *const __CFDictionary;
+type CFMutableDictionaryRef = *mut __CFDictionary;
+
+#[repr(C)]
+struct __CFNumber(c_void);
+type CFNumberRef = *const __CFNumber;
+type CFNumberType = CFIndex;
+const kCFNumberSInt32Type: CFNumberType = 3;
+
 #[cfg_attr(target_os = "macos", link(name = "CoreFoundation", kind = "framework"))]
 extern "C" {
   static kCFRunLoopCommonModes: CFStringRef;
@@ -286,8 +839,33 @@ extern "C" {
   fn CFRunLoopRun();
   fn CFRunLoopStop(rl: CFRunLoopRef);
 
+  fn CFRunLoopAddTimer(rl: CFRunLoopRef, timer: CFRunLoopTimerRef, mode: CFRunLoopMode);
+  fn CFRunLoopTimerCreate(
+    allocator: *mut c_void,
+    fireDate: CFAbsoluteTime,
+    interval: CFTimeInterval,
+    flags: CFOptionFlags,
+    order: CFIndex,
+    callout: CFRunLoopTimerCallBack,
+    context: *mut CFRunLoopTimerContext,
+  ) -> CFRunLoopTimerRef;
+  fn CFRunLoopTimerSetNextFireDate(timer: CFRunLoopTimerRef, fireDate: CFAbsoluteTime);
+  fn CFRunLoopTimerIsValid(timer: CFRunLoopTimerRef) -> Boolean;
+  fn CFRunLoopTimerInvalidate(timer: CFRunLoopTimerRef);
+
+  fn CFAbsoluteTimeGetCurrent() -> CFAbsoluteTime;
+
   fn CFRetain(cf: CFTypeRef) -> CFTypeRef;
   fn CFRelease(cf: CFTypeRef);
+
+  fn CFStringCreateWithCString(
+    alloc: *mut c_void,
+    cStr: *const i8,
+    encoding: CFStringEncoding,
+  ) -> CFStringRef;
+
+  fn CFNumberCreate(allocator: *mut c_void, theType: CFNumberType, valuePtr: *const c_void) -> CFNumberRef;
+  fn CFDictionarySetValue(theDict: CFMutableDictionaryRef, key: *const c_void, value: *const c_void);
 }
 
 //
@@ -329,8 +907,12 @@ type IOServiceInterestCallback = unsafe extern "C" fn(
   messageArgument: *mut c_void,
 );
 
+type IOServiceMatchingCallback = extern "C" fn(refCon: *mut c_void, iterator: io_iterator_t);
+
 #[cfg_attr(target_os = "macos", link(name = "IOKit", kind = "framework"))]
 extern "C" {
+  static kIOMasterPortDefault: mach_port_t;
+
   fn IORegisterForSystemPower(
     refcon: *mut c_void,
     thePortRef: *mut IONotificationPortRef,
@@ -339,11 +921,47 @@ extern "C" {
   ) -> io_connect_t;
   fn IODeregisterForSystemPower(notifier: *mut io_object_t) -> IOReturn;
 
+  fn IONotificationPortCreate(masterPort: mach_port_t) -> IONotificationPortRef;
   fn IONotificationPortGetRunLoopSource(notify: IONotificationPortRef) -> CFRunLoopSourceRef;
   fn IONotificationPortDestroy(notify: IONotificationPortRef);
 
   fn IOAllowPowerChange(kernelPort: io_connect_t, notificationID: *const c_void) -> IOReturn;
   fn IOCancelPowerChange(kernelPort: io_connect_t, notificationID: *const c_void) -> IOReturn;
 
+  fn IOServiceMatching(name: *const c_char) -> CFMutableDictionaryRef;
+  fn IOServiceAddMatchingNotification(
+    notifyPort: IONotificationPortRef,
+    notificationType: *const c_char,
+    matching: CFDictionaryRef,
+    callback: IOServiceMatchingCallback,
+    refCon: *mut c_void,
+    notification: *mut io_iterator_t,
+  ) -> kern_return_t;
+  fn IOIteratorNext(iterator: io_iterator_t) -> io_object_t;
+  fn IOObjectRelease(object: io_object_t) -> kern_return_t;
+
   fn IOServiceClose(connect: io_connect_t) -> kern_return_t;
 }
+
+//
+// IOKit/pwr_mgt (power assertions)
+//
+
+type IOPMAssertionID = u32;
+type IOPMAssertionLevel = u32;
+
+const kIOPMAssertionLevelOn: IOPMAssertionLevel = 255;
+
+#[cfg_attr(target_os = "macos", link(name = "IOKit", kind = "framework"))]
+extern "C" {
+  static kIOPMAssertionTypePreventUserIdleSystemSleep: CFStringRef;
+  static kIOPMAssertionTypePreventUserIdleDisplaySleep: CFStringRef;
+
+  fn IOPMAssertionCreateWithName(
+    assertionType: CFStringRef,
+    assertionLevel: IOPMAssertionLevel,
+    assertionName: CFStringRef,
+    assertionID: *mut IOPMAssertionID,
+  ) -> IOReturn;
+  fn IOPMAssertionRelease(assertionID: IOPMAssertionID) -> IOReturn;
+}
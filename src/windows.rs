@@ -0,0 +1,229 @@
+#![allow(non_camel_case_types)]
+#![allow(non_snake_case)]
+#![allow(non_upper_case_globals)]
+
+use std::{
+  ffi::c_void,
+  ptr::null_mut,
+  sync::{
+    atomic::{AtomicU32, Ordering},
+    Mutex,
+  },
+};
+
+use tracing::{trace, warn};
+
+use crate::{Error, EventHandler};
+
+pub struct Naptime {
+  handle: HPOWERNOTIFY,
+  // Windows invokes our callback with this as the context pointer for as long as `handle` is
+  // registered, so it must outlive the registration
+  state: *mut ThreadState,
+}
+
+// SAFETY: the only access to `state` after construction happens inside `power_notify_callback`,
+// which synchronizes through the Mutex it contains
+unsafe impl Send for Naptime {}
+
+impl Naptime {
+  pub fn new<E>(event_handler: E) -> Result<Self, Error>
+  where
+    E: EventHandler,
+  {
+    let state = Box::into_raw(Box::new(ThreadState {
+      event_handler: Mutex::new(Box::new(event_handler)),
+    }));
+
+    let params = DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+      callback: power_notify_callback,
+      context: state as *mut c_void,
+    };
+
+    let mut handle: HPOWERNOTIFY = null_mut();
+    // SAFETY: `params` only needs to be valid for the duration of this call; Windows copies out what
+    // it needs to invoke the callback later via the context pointer
+    let ret = unsafe {
+      PowerRegisterSuspendResumeNotification(
+        DEVICE_NOTIFY_CALLBACK,
+        &params as *const DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as HANDLE,
+        &mut handle,
+      )
+    };
+    if ret != ERROR_SUCCESS {
+      // SAFETY: registration failed, so Windows never got a copy of `state` to call back into
+      unsafe { drop(Box::from_raw(state)) };
+      return Err(Error(format!(
+        "PowerRegisterSuspendResumeNotification failed. code={:08x}",
+        ret
+      )));
+    }
+
+    Ok(Self { handle, state })
+  }
+
+  /// Prevents the system from sleeping due to user idleness. Implemented with
+  /// `SetThreadExecutionState(ES_SYSTEM_REQUIRED)`, the same mechanism behind `powercfg /requestsoverride`
+  /// and media players that keep a laptop awake during playback. `reason` is accepted for API parity
+  /// with the other platforms, but Windows has no equivalent of an assertion name to show it.
+  pub fn prevent_idle_system_sleep(_reason: &str) -> Result<PowerAssertion, Error> {
+    SYSTEM_ASSERTIONS.fetch_add(1, Ordering::SeqCst);
+    apply_execution_state();
+    Ok(PowerAssertion(AssertionKind::System))
+  }
+
+  /// Prevents the display from sleeping due to user idleness, via `SetThreadExecutionState(ES_DISPLAY_REQUIRED)`.
+  pub fn prevent_display_sleep(_reason: &str) -> Result<PowerAssertion, Error> {
+    DISPLAY_ASSERTIONS.fetch_add(1, Ordering::SeqCst);
+    apply_execution_state();
+    Ok(PowerAssertion(AssertionKind::Display))
+  }
+}
+
+impl Drop for Naptime {
+  fn drop(&mut self) {
+    // SAFETY: self.handle was returned by a successful PowerRegisterSuspendResumeNotification call
+    let ret = unsafe { PowerUnregisterSuspendResumeNotification(self.handle) };
+    if ret != ERROR_SUCCESS {
+      warn!("PowerUnregisterSuspendResumeNotification failed. code={:08x}", ret);
+    }
+    // SAFETY: the notification is unregistered above, so Windows can no longer call back into this
+    // pointer
+    unsafe { drop(Box::from_raw(self.state)) };
+  }
+}
+
+struct ThreadState {
+  event_handler: Mutex<Box<dyn EventHandler>>,
+}
+
+/// Windows has no mechanism analogous to IOKit's deferred power-change acknowledgment or logind's
+/// delay inhibitor: a `PowerRegisterSuspendResumeNotification` callback must return before the
+/// system proceeds, with no way to hold it open past that. This backend still calls
+/// [`EventHandler::sleep_query_deferred`] and [`EventHandler::sleep_deferred`] (so a handler that
+/// only overrides those fires here too), but it does so synchronously within the callback, with
+/// `allow()` a no-op and `cancel()` just logging — there's no way to actually defer or veto.
+pub struct SleepAck;
+
+impl SleepAck {
+  pub fn allow(self) {}
+
+  pub fn cancel(self) {
+    warn!("sleep_query denied sleep, but Windows offers no way to veto PBT_APMSUSPEND");
+  }
+}
+
+unsafe extern "system" fn power_notify_callback(context: *mut c_void, event_type: u32, _setting: *mut c_void) -> u32 {
+  // SAFETY: `context` is the ThreadState pointer from Naptime::new, which outlives every call to this
+  // callback because Naptime::drop unregisters it before freeing the pointer
+  let state = unsafe { &*(context as *const ThreadState) };
+  let mut event_handler = state.event_handler.lock().unwrap();
+
+  match event_type {
+    PBT_APMSUSPEND => {
+      trace!("PBT_APMSUSPEND");
+      // Unlike IOKit, Windows gives callbacks no way to defer or veto PBT_APMSUSPEND past returning
+      // from this function. We still go through the `_deferred` methods (the primary extension
+      // point, per chunk0-2) rather than `sleep_query`/`sleep` directly, so a handler that only
+      // overrides the deferred variants still fires here, the same as on macOS (the Linux backend
+      // calls `sleep_query_deferred` but plain `sleep`, never `sleep_deferred`). The
+      // no-op `SleepAck` makes this synchronous regardless, and a Deny response is necessarily
+      // best-effort: it's logged (see `SleepAck::cancel`), but the sleep proceeds regardless.
+      event_handler.sleep_query_deferred(SleepAck);
+      event_handler.sleep_deferred(SleepAck);
+    }
+    PBT_APMRESUMEAUTOMATIC => {
+      trace!("PBT_APMRESUMEAUTOMATIC");
+      event_handler.wake();
+    }
+    _ => {}
+  }
+
+  ERROR_SUCCESS
+}
+
+/// An RAII guard obtained from [`Naptime::prevent_idle_system_sleep`] or
+/// [`Naptime::prevent_display_sleep`]. Unlike macOS's `IOPMAssertion`, Windows has no per-assertion
+/// handle; `SetThreadExecutionState` just takes the OR of whatever flags should currently apply, so
+/// this guard is backed by a process-wide reference count that recomputes and reapplies the combined
+/// flags on every acquire and release.
+pub struct PowerAssertion(AssertionKind);
+
+enum AssertionKind {
+  System,
+  Display,
+}
+
+impl Drop for PowerAssertion {
+  fn drop(&mut self) {
+    match self.0 {
+      AssertionKind::System => {
+        SYSTEM_ASSERTIONS.fetch_sub(1, Ordering::SeqCst);
+      }
+      AssertionKind::Display => {
+        DISPLAY_ASSERTIONS.fetch_sub(1, Ordering::SeqCst);
+      }
+    }
+    apply_execution_state();
+  }
+}
+
+static SYSTEM_ASSERTIONS: AtomicU32 = AtomicU32::new(0);
+static DISPLAY_ASSERTIONS: AtomicU32 = AtomicU32::new(0);
+
+fn apply_execution_state() {
+  let mut flags = ES_CONTINUOUS;
+  if SYSTEM_ASSERTIONS.load(Ordering::SeqCst) > 0 {
+    flags |= ES_SYSTEM_REQUIRED;
+  }
+  if DISPLAY_ASSERTIONS.load(Ordering::SeqCst) > 0 {
+    flags |= ES_DISPLAY_REQUIRED;
+  }
+  // SAFETY: SetThreadExecutionState has no preconditions beyond being called with valid flag bits
+  unsafe { SetThreadExecutionState(flags) };
+}
+
+//
+// Win32
+//
+
+type HANDLE = *mut c_void;
+type DWORD = u32;
+type ULONG = u32;
+type PVOID = *mut c_void;
+type HPOWERNOTIFY = *mut c_void;
+
+const DEVICE_NOTIFY_CALLBACK: DWORD = 2;
+const ERROR_SUCCESS: DWORD = 0;
+
+const PBT_APMSUSPEND: DWORD = 0x0004;
+const PBT_APMRESUMEAUTOMATIC: DWORD = 0x0012;
+
+type EXECUTION_STATE = DWORD;
+const ES_CONTINUOUS: EXECUTION_STATE = 0x8000_0000;
+const ES_SYSTEM_REQUIRED: EXECUTION_STATE = 0x0000_0001;
+const ES_DISPLAY_REQUIRED: EXECUTION_STATE = 0x0000_0002;
+
+type DEVICE_NOTIFY_CALLBACK_ROUTINE =
+  unsafe extern "system" fn(context: PVOID, event_type: ULONG, setting: PVOID) -> ULONG;
+
+#[repr(C)]
+struct DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+  callback: DEVICE_NOTIFY_CALLBACK_ROUTINE,
+  context: PVOID,
+}
+
+#[link(name = "powrprof")]
+extern "system" {
+  fn PowerRegisterSuspendResumeNotification(
+    Flags: DWORD,
+    Recipient: HANDLE,
+    RegistrationHandle: *mut HPOWERNOTIFY,
+  ) -> DWORD;
+  fn PowerUnregisterSuspendResumeNotification(RegistrationHandle: HPOWERNOTIFY) -> DWORD;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+  fn SetThreadExecutionState(esFlags: EXECUTION_STATE) -> EXECUTION_STATE;
+}